@@ -1,9 +1,17 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
 use bytemuck::{Pod, Zeroable};
-use bevy_ggrs::{GgrsAppExtension, GgrsPlugin, GgrsSchedule, Session, AddRollbackCommandExtension, Rollback, PlayerInputs};
-use ggrs::{Config, SessionBuilder, PlayerType, PlayerHandle, UdpNonBlockingSocket};
+use bevy_ggrs::{GgrsApp, GgrsPlugin, GgrsSchedule, ReadInputs, Session, AddRollbackCommandExtension, Rollback, PlayerInputs, LocalInputs, LocalPlayers};
+use bevy_rapier2d::prelude::*;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use ggrs::{Config, InputStatus, SessionBuilder, PlayerType, PlayerHandle, UdpNonBlockingSocket};
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
 const FPS: usize = 60;
@@ -19,6 +27,14 @@ const MAP_SIZE: Vec2 = Vec2{x: 1600., y: 1200.};
 
 const TIME_TO_RELOAD: f32 = 0.5;
 
+const PLAYER_MAX_HEALTH: i32 = 100;
+const BULLET_DAMAGE: i32 = 25;
+
+const BULLET_LIFETIME: f32 = 1.;
+// A bullet can't damage the player who fired it during these first few
+// frames, so backing away from your own muzzle doesn't kill you.
+const BULLET_SPAWN_GRACE: f32 = 6. * FRAME_TIME;
+
 const INPUT_UP: u8 = 1 << 0;
 const INPUT_DOWN: u8 = 1 << 1;
 const INPUT_LEFT: u8 = 1 << 2;
@@ -37,10 +53,32 @@ impl Config for GgrsConfig {
     type Address = SocketAddr;
 }
 
+// Fixed-size and made only of byte arrays so it stays `Pod`/`Zeroable` for
+// GGRS with no implicit padding: `frame` and `signature` carry the data an
+// ed25519 signature over `(frame, val)` needs to be checked in `move_players`.
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
 pub struct BoxInput {
-    pub val: u8
+    pub val: u8,
+    pub frame: [u8; 4],
+    pub signature: [u8; 64],
+}
+
+// serde only derives Serialize/Deserialize for arrays up to 32 elements, so
+// `signature`'s 64 bytes need a hand-written impl for `--record`/`--replay`.
+impl Serialize for BoxInput {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.val, self.frame, &self.signature[..]).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BoxInput {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (val, frame, signature): (u8, [u8; 4], Vec<u8>) = Deserialize::deserialize(deserializer)?;
+        let mut sig = [0u8; 64];
+        sig.copy_from_slice(&signature);
+        Ok(BoxInput { val, frame, signature: sig })
+    }
 }
 
 #[derive(StructOpt, Resource)]
@@ -49,75 +87,359 @@ struct Opt {
     local_port: u16,
     #[structopt(short, long)]
     players: Vec<String>,
+    #[structopt(short, long)]
+    spectators: Vec<String>,
+    // Only read in pure-spectator mode (no --players), since a spectator
+    // session has to know up front how many player slots the match it's
+    // joining has.
+    #[structopt(long)]
+    num_players: Option<usize>,
+    #[structopt(long)]
+    sync_test: Option<usize>,
+    // Reject/ignore a remote player's input if it isn't signed by the
+    // ed25519 key they handed us during the pre-session handshake.
+    #[structopt(long)]
+    signed_inputs: bool,
+    #[structopt(long)]
+    record: Option<PathBuf>,
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+}
+
+// The local node's identity for signing its own outgoing inputs.
+#[derive(Resource)]
+pub struct PlayerIdentity {
+    signing_key: SigningKey,
+}
+
+// Public keys collected from every player during the pre-session handshake,
+// keyed by `PlayerHandle`, used to verify remote inputs in `move_players`.
+// `last_frame` remembers the highest signed frame accepted from each handle
+// so a captured, validly-signed input can't be replayed at a later frame.
+#[derive(Resource, Default)]
+pub struct RemoteKeys {
+    enabled: bool,
+    keys: HashMap<PlayerHandle, VerifyingKey>,
+    last_frame: HashMap<PlayerHandle, u32>,
+}
+
+// Per-frame counter stamped into every outgoing `BoxInput` so a captured
+// input can't be replayed on a later frame.
+#[derive(Default, Resource)]
+pub struct FrameCounter {
+    val: u32
+}
+
+// A player's spawn point, captured once so a recorded match can be
+// reconstructed with the exact same starting positions and colors.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpawnRecord {
+    translation: [f32; 3],
+    hue: f32,
+}
+
+fn spawn_layout(pnum: usize) -> Vec<SpawnRecord> {
+    (0..pnum).map(|i| SpawnRecord {
+        translation: [i as f32 * 75., 0., 0.],
+        hue: (i as f32) / (pnum as f32) * 360.,
+    }).collect()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplayHeader {
+    num_players: usize,
+    spawns: Vec<SpawnRecord>,
+}
+
+// Mirrors `ggrs::InputStatus` so a recorded frame can be serialized without
+// depending on ggrs's type implementing serde traits.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum RecordedStatus {
+    Confirmed,
+    Predicted,
+    Disconnected,
+}
+
+impl From<InputStatus> for RecordedStatus {
+    fn from(status: InputStatus) -> Self {
+        match status {
+            InputStatus::Confirmed => RecordedStatus::Confirmed,
+            InputStatus::Predicted => RecordedStatus::Predicted,
+            InputStatus::Disconnected => RecordedStatus::Disconnected,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecordedFrame {
+    inputs: Vec<(BoxInput, RecordedStatus)>,
+}
+
+// Writes one bincode-encoded `RecordedFrame` per confirmed frame when
+// `--record` is set. `GgrsSchedule` re-runs `record_inputs` once per
+// resimulated frame after a rollback, so frames are buffered by frame
+// number (overwriting any earlier, now-stale prediction for that frame)
+// and only serialized to disk once the session confirms they're final.
+#[derive(Resource)]
+pub struct Recorder {
+    writer: Option<BufWriter<File>>,
+    pending: HashMap<i32, RecordedFrame>,
+    flushed_through: i32,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder { writer: None, pending: HashMap::new(), flushed_through: -1 }
+    }
+}
+
+// Recorded match loaded wholesale into memory and replayed back through
+// `input()` instead of the keyboard, one frame per handle per tick.
+#[derive(Resource)]
+pub struct ReplayInputs {
+    header: ReplayHeader,
+    frames: Vec<RecordedFrame>,
+    cursor: Vec<usize>,
 }
 
 
 #[derive(Default, Component)]
 pub struct Player {
     handle: usize,
-    reload_time: f32
+    reload_time: f32,
+    // Carried over on respawn so a player keeps the same color for the
+    // whole match instead of a second, unrelated formula picking a new one.
+    hue: f32,
 }
 
 #[derive(Default, Component)]
-pub struct Bullet;
+pub struct Bullet {
+    handle: usize
+}
 
 #[derive(Default, Component, Reflect)]
 pub struct LifeTime {
     val: f32
 }
 
-#[derive(Default, Component, Reflect)]
-pub struct Velocity {
-    val: Vec3
+#[derive(Default, Component, Reflect, Clone)]
+pub struct Health {
+    val: i32
+}
+
+// HUD text showing one player's reload cooldown. Lives outside the
+// `GgrsSchedule` world, so it's never touched by rollback.
+#[derive(Component)]
+pub struct ReloadBarText {
+    handle: usize
 }
 
+// HUD text showing the session's network stats and current rollback frame.
+#[derive(Component)]
+pub struct NetworkStatsText;
 
 
-pub fn input(_handle: In<PlayerHandle>, keyboard_input: Res<Input<KeyCode>>) -> BoxInput {
-    let mut input: u8 = 0;
+// Runs in the `ReadInputs` schedule and collects every local player's input
+// for the frame into the `LocalInputs` resource GGRS expects.
+pub fn input(
+    mut commands: Commands,
+    local_players: Res<LocalPlayers>,
+    keyboard_input: Res<Input<KeyCode>>,
+    identity: Res<PlayerIdentity>,
+    mut frame_counter: ResMut<FrameCounter>,
+    mut replay: Option<ResMut<ReplayInputs>>,
+) {
+    let mut local_inputs = bevy::utils::HashMap::new();
+
+    for &handle in &local_players.0 {
+        if let Some(replay) = replay.as_mut() {
+            let cursor = replay.cursor[handle];
+            let recorded = replay.frames[cursor].inputs[handle].0;
+            replay.cursor[handle] += 1;
+            local_inputs.insert(handle, recorded);
+            continue;
+        }
 
-    if keyboard_input.pressed(KeyCode::W) {
-        input |= INPUT_UP;
-    }
-    if keyboard_input.pressed(KeyCode::A) {
-        input |= INPUT_LEFT;
-    }
-    if keyboard_input.pressed(KeyCode::S) {
-        input |= INPUT_DOWN;
-    }
-    if keyboard_input.pressed(KeyCode::D) {
-        input |= INPUT_RIGHT;
-    }
-    if keyboard_input.pressed(KeyCode::Up) {
-        input |= INPUT_UP2;
-    }
-    if keyboard_input.pressed(KeyCode::Left) {
-        input |= INPUT_LEFT2;
-    }
-    if keyboard_input.pressed(KeyCode::Down) {
-        input |= INPUT_DOWN2;
-    }
-    if keyboard_input.pressed(KeyCode::Right) {
-        input |= INPUT_RIGHT2;
+        let mut input: u8 = 0;
+
+        if keyboard_input.pressed(KeyCode::W) {
+            input |= INPUT_UP;
+        }
+        if keyboard_input.pressed(KeyCode::A) {
+            input |= INPUT_LEFT;
+        }
+        if keyboard_input.pressed(KeyCode::S) {
+            input |= INPUT_DOWN;
+        }
+        if keyboard_input.pressed(KeyCode::D) {
+            input |= INPUT_RIGHT;
+        }
+        if keyboard_input.pressed(KeyCode::Up) {
+            input |= INPUT_UP2;
+        }
+        if keyboard_input.pressed(KeyCode::Left) {
+            input |= INPUT_LEFT2;
+        }
+        if keyboard_input.pressed(KeyCode::Down) {
+            input |= INPUT_DOWN2;
+        }
+        if keyboard_input.pressed(KeyCode::Right) {
+            input |= INPUT_RIGHT2;
+        }
+
+        let frame = frame_counter.val;
+        frame_counter.val += 1;
+        let frame = frame.to_le_bytes();
+
+        let mut signed = [0u8; 5];
+        signed[0] = input;
+        signed[1..].copy_from_slice(&frame);
+        let signature = identity.signing_key.sign(&signed);
+
+        local_inputs.insert(handle, BoxInput { val: input, frame, signature: signature.to_bytes() });
     }
 
-    BoxInput { val: input }
+    commands.insert_resource(LocalInputs::<GgrsConfig>(local_inputs));
 }
 
 
 
 
+// Assembles the app for every session kind. The `GgrsSchedule` pipeline is
+// the whole point: `move_players` turns input into velocities, then Rapier's
+// own system sets are run in order so it only ever advances inside a
+// rollback-able frame, never on its own `Update`-schedule timer.
+fn run_app(
+    session: Session<GgrsConfig>,
+    identity: PlayerIdentity,
+    remote_keys: RemoteKeys,
+    recorder: Recorder,
+    replay: Option<ReplayInputs>,
+) {
+    let mut app = App::new();
+
+    app
+        .add_plugins(GgrsPlugin::<GgrsConfig>::default())
+        .set_rollback_schedule_fps(FPS)
+        .add_systems(ReadInputs, input)
+        .rollback_component_with_clone::<Transform>()
+        .rollback_component_with_clone::<Health>()
+        .rollback_component_with_clone::<Velocity>()
+        // `RapierContext` itself isn't rolled back: it doesn't implement
+        // `Clone`/`Copy`/`Reflect`, so instead `PhysicsSet::SyncBackend`
+        // re-derives it every step from the `Transform`/`Velocity` state
+        // that IS rolled back.
+        .add_plugins(DefaultPlugins)
+        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().with_default_system_setup(false))
+        .insert_resource(RapierConfiguration {
+            timestep_mode: TimestepMode::Fixed { dt: FRAME_TIME, substeps: 1 },
+            ..default()
+        })
+        // `PhysicsSet` is only a `SystemSet`, not a system itself, so the
+        // Rapier stages have to be wired in the same two-step way the
+        // plugin's own `build()` does: declare their order with
+        // `configure_sets`, then add the plugin's systems into each set.
+        .configure_sets(GgrsSchedule, (
+            PhysicsSet::SyncBackend,
+            PhysicsSet::StepSimulation,
+            PhysicsSet::Writeback,
+        ).chain())
+        .add_systems(GgrsSchedule, (record_inputs, move_players).chain().before(PhysicsSet::SyncBackend))
+        .add_systems(GgrsSchedule, RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend).in_set(PhysicsSet::SyncBackend))
+        .add_systems(GgrsSchedule, RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation).in_set(PhysicsSet::StepSimulation))
+        .add_systems(GgrsSchedule, RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback).in_set(PhysicsSet::Writeback))
+        .add_systems(GgrsSchedule, (resolve_hits, age_mortals).chain().after(PhysicsSet::Writeback))
+        .insert_resource(session)
+        .insert_resource(identity)
+        .insert_resource(remote_keys)
+        .insert_resource(recorder)
+        .insert_resource(FrameCounter::default())
+        .add_systems(Startup, (setup, setup_hud))
+        .add_systems(Update, update_hud);
+
+    if let Some(replay) = replay {
+        app.insert_resource(replay);
+    }
+
+    app.run();
+}
+
 fn main() {
     let opt = Opt::from_args();
 
+    let identity = PlayerIdentity { signing_key: SigningKey::generate(&mut rand::rngs::OsRng) };
+
+    // Replays a `--record`ed match offline by feeding its recorded inputs
+    // back through `input()` on a local, no-resimulation SyncTest session.
+    if let Some(replay_path) = &opt.replay {
+        info!("LOADING REPLAY...");
+
+        let mut reader = BufReader::new(File::open(replay_path).unwrap());
+        let header: ReplayHeader = bincode::deserialize_from(&mut reader).unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = bincode::deserialize_from::<_, RecordedFrame>(&mut reader) {
+            frames.push(frame);
+        }
+
+        let num_players = header.num_players;
+        let cursor = vec![0; num_players];
+        let replay = ReplayInputs { header, frames, cursor };
+
+        let sess = SessionBuilder::<GgrsConfig>::new()
+            .with_num_players(num_players)
+            .with_check_distance(0)
+            .start_synctest_session()
+            .unwrap();
+
+        run_app(Session::SyncTest(sess), identity, RemoteKeys::default(), Recorder::default(), Some(replay));
+        return;
+    }
+
+    // Re-simulates the last `check_distance` frames every tick and compares
+    // state checksums, surfacing rollback desyncs on a single machine.
+    if let Some(check_distance) = opt.sync_test {
+        info!("STARTING SYNCTEST SESSION...");
+
+        let sess = SessionBuilder::<GgrsConfig>::new()
+            .with_num_players(opt.players.len())
+            .with_check_distance(check_distance)
+            .start_synctest_session()
+            .unwrap();
+
+        run_app(Session::SyncTest(sess), identity, RemoteKeys::default(), Recorder::default(), None);
+        return;
+    }
+
+    let socket = UdpNonBlockingSocket::bind_to_port(opt.local_port).unwrap();
+
+    // A node started with no players of its own and at least one spectator
+    // address is just tuning in to watch a match hosted elsewhere.
+    if opt.players.is_empty() {
+        let host_addr: SocketAddr = opt.spectators.first()
+            .expect("--spectators must name a host address when running with no --players")
+            .parse().unwrap();
+        let num_players = opt.num_players
+            .expect("--num-players is required in pure-spectator mode, to match the session being watched");
+
+        info!("STARTING SPECTATOR SESSION...");
+
+        let sess = SessionBuilder::<GgrsConfig>::new()
+            .with_num_players(num_players)
+            .start_spectator_session(host_addr, socket);
+
+        run_app(Session::Spectator(sess), identity, RemoteKeys::default(), Recorder::default(), None);
+        return;
+    }
+
     let mut sess_build = SessionBuilder::<GgrsConfig>::new()
         .with_num_players(opt.players.len())
         .with_desync_detection_mode(ggrs::DesyncDetection::On { interval: 10 }) // (optional) set how often to exchange state checksums
-        .with_max_prediction_window(12) // (optional) set max prediction window
+        .with_max_prediction_window(12).unwrap() // (optional) set max prediction window
         .with_input_delay(2); // (optional) set input delay for the local player
 
         info!("ADDING PLAYERS...");
-    
+
     for (i, player_addr) in opt.players.iter().enumerate() {
         // local player
         if player_addr == "localhost" {
@@ -130,24 +452,40 @@ fn main() {
     }
 
     info!("PLAYERS ADDED");
-    
-    let socket = UdpNonBlockingSocket::bind_to_port(opt.local_port).unwrap();
+
+    for (i, spectator_addr) in opt.spectators.iter().enumerate() {
+        let remote_addr: SocketAddr = spectator_addr.parse().unwrap();
+        sess_build = sess_build.add_player(PlayerType::Spectator(remote_addr), opt.players.len() + i).unwrap();
+    }
+
+    info!("SPECTATORS ADDED");
+
+    let remote_keys = if opt.signed_inputs {
+        info!("EXCHANGING PUBLIC KEYS...");
+        exchange_public_keys(opt.local_port, &identity.signing_key, &opt.players)
+    } else {
+        RemoteKeys::default()
+    };
+
+    let recorder = match &opt.record {
+        Some(record_path) => {
+            info!("RECORDING TO {:?}...", record_path);
+
+            let mut writer = BufWriter::new(File::create(record_path).unwrap());
+            let header = ReplayHeader {
+                num_players: opt.players.len(),
+                spawns: spawn_layout(opt.players.len()),
+            };
+            bincode::serialize_into(&mut writer, &header).unwrap();
+
+            Recorder { writer: Some(writer), ..Recorder::default() }
+        }
+        None => Recorder::default(),
+    };
+
     let sess = sess_build.start_p2p_session(socket).unwrap();
-        
-    App::new()
-        .add_ggrs_plugin(GgrsPlugin::<GgrsConfig>::new()
-            .with_update_frequency(FPS)
-            .with_input_system(input)
-            .register_rollback_component::<Transform>()
-        )
-        .add_systems(GgrsSchedule, (
-            move_players,
-            move_objects.after(move_players),
-            age_mortals.after(move_objects)
-        )).add_plugins(DefaultPlugins)
-        .insert_resource(Session::P2P(sess))
-        .add_systems(Startup, setup)
-    .run();
+
+    run_app(Session::P2P(sess), identity, remote_keys, recorder, None);
 }
 
 
@@ -157,7 +495,8 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    session: Res<Session<GgrsConfig>>
+    session: Res<Session<GgrsConfig>>,
+    replay: Option<Res<ReplayInputs>>,
 ) {
     let pnum = match &*session {
         Session::SyncTest(s) => s.num_players(),
@@ -165,15 +504,29 @@ fn setup(
         Session::Spectator(s) => s.num_players(),
     };
 
-    for i in 0..pnum {
+    // A replay reconstructs the exact spawn layout it was recorded with
+    // instead of recomputing it, so the match plays back bit-for-bit even
+    // if `spawn_layout`'s formula changes later.
+    let spawns = match &replay {
+        Some(replay) => replay.header.spawns.clone(),
+        None => spawn_layout(pnum),
+    };
+
+    for (i, spawn) in spawns.iter().enumerate() {
         println!("{}", (i as f32) / (pnum as f32 + 1.));
         commands.spawn((MaterialMesh2dBundle {
                 mesh: meshes.add(shape::Circle::new(PLAYER_SIZE).into()).into(),
-                material: materials.add(ColorMaterial::from(Color::Hsla { hue: (i as f32) / (pnum as f32) * 360., saturation: 0.75, lightness: 0.5, alpha: 1. })),
-                transform: Transform::from_translation(Vec3 { x: i as f32 * 75., y: 0., z: 0. }),
+                material: materials.add(ColorMaterial::from(Color::Hsla { hue: spawn.hue, saturation: 0.75, lightness: 0.5, alpha: 1. })),
+                transform: Transform::from_translation(Vec3::from(spawn.translation)),
                 ..default()
             },
-            Player {handle: i, reload_time: 0.},
+            Player {handle: i, reload_time: 0., hue: spawn.hue},
+            Health {val: PLAYER_MAX_HEALTH},
+            RigidBody::Dynamic,
+            Collider::ball(PLAYER_SIZE),
+            Velocity::zero(),
+            GravityScale(0.),
+            LockedAxes::ROTATION_LOCKED,
         )).add_rollback();
     }
 
@@ -189,17 +542,110 @@ fn setup(
 
 
 
+// Captures the exact inputs GGRS resolved for this tick so a match can be
+// reconstructed bit-for-bit offline by feeding them back through `input()`.
+// Only a P2P session ever opens a `--record` writer, so nothing else
+// records; its rollbacks are why the confirmed-frame buffering below exists.
+fn record_inputs(
+    mut recorder: ResMut<Recorder>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    session: Res<Session<GgrsConfig>>,
+) {
+    if recorder.writer.is_none() { return; }
+    let Session::P2P(session) = &*session else { return; };
+
+    let frame = session.current_frame();
+    let recorded = RecordedFrame {
+        inputs: inputs.iter().map(|(input, status)| (*input, (*status).into())).collect(),
+    };
+    recorder.pending.insert(frame, recorded);
+
+    // Flush every frame up through the newly confirmed one, in order.
+    // A resimulated frame just overwrote its stale entry above, so this
+    // never re-serializes or reorders anything already on disk.
+    let confirmed = session.confirmed_frame();
+    let mut next = recorder.flushed_through + 1;
+    while next <= confirmed {
+        let Some(recorded_frame) = recorder.pending.remove(&next) else { break; };
+        bincode::serialize_into(recorder.writer.as_mut().unwrap(), &recorded_frame).unwrap();
+        recorder.flushed_through = next;
+        next += 1;
+    }
+}
+
+fn verify_input(box_input: &BoxInput, handle: PlayerHandle, remote_keys: &RemoteKeys) -> bool {
+    let Some(verifying_key) = remote_keys.keys.get(&handle) else {
+        return false;
+    };
+
+    let mut signed = [0u8; 5];
+    signed[0] = box_input.val;
+    signed[1..].copy_from_slice(&box_input.frame);
+
+    verifying_key.verify(&signed, &Signature::from_bytes(&box_input.signature)).is_ok()
+}
+
+// Trades ed25519 public keys with every remote peer over a plain UDP
+// handshake on `local_port + 1000`, so `local_port` itself stays free for
+// the GGRS session socket. Blocks until every remote has responded.
+fn exchange_public_keys(local_port: u16, local_key: &SigningKey, players: &[String]) -> RemoteKeys {
+    let socket = UdpSocket::bind(("0.0.0.0", local_port + 1000)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let local_public = local_key.verifying_key();
+    let mut keys = HashMap::new();
+
+    for (handle, player_addr) in players.iter().enumerate() {
+        if player_addr == "localhost" {
+            keys.insert(handle, local_public);
+            continue;
+        }
+
+        let remote_addr: SocketAddr = player_addr.parse().unwrap();
+        let handshake_addr = SocketAddr::new(remote_addr.ip(), remote_addr.port() + 1000);
+
+        loop {
+            socket.send_to(local_public.as_bytes(), handshake_addr).unwrap();
+
+            let mut buf = [0u8; 32];
+            if let Ok((32, from)) = socket.recv_from(&mut buf) {
+                if from == handshake_addr {
+                    keys.insert(handle, VerifyingKey::from_bytes(&buf).unwrap());
+                    break;
+                }
+            }
+        }
+    }
+
+    RemoteKeys { enabled: true, keys, last_frame: HashMap::new() }
+}
+
 fn move_players(
-    mut players: Query<(&mut Transform, &mut Player), With<Rollback>>,
+    mut players: Query<(&Transform, &mut Velocity, &mut Player), With<Rollback>>,
 
     mut commands: Commands,
-    
+
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    inputs: Res<PlayerInputs<GgrsConfig>>
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut remote_keys: ResMut<RemoteKeys>,
 ) {
-    for (mut player_t, mut player) in players.iter_mut() {
-        let input = inputs[player.handle].0.val;
+    for (player_t, mut player_v, mut player) in players.iter_mut() {
+        let box_input = inputs[player.handle].0;
+        let frame = u32::from_le_bytes(box_input.frame);
+        let is_replay = remote_keys.last_frame.get(&player.handle).is_some_and(|&last| frame <= last);
+
+        let input = if remote_keys.enabled && (is_replay || !verify_input(&box_input, player.handle, &remote_keys)) {
+            // Signature didn't check out, or this frame was already
+            // consumed for this handle: drop the input rather than trust
+            // a possibly-forged or replayed one.
+            0
+        } else {
+            if remote_keys.enabled {
+                remote_keys.last_frame.insert(player.handle, frame);
+            }
+            box_input.val
+        };
 
         let mut delta_pos = Vec3{x: 0., y: 0., z: 0.};
 
@@ -208,7 +654,7 @@ fn move_players(
         if input & INPUT_RIGHT != 0 {delta_pos.x += 1.;}
         if input & INPUT_LEFT  != 0 {delta_pos.x -= 1.;}
 
-        player_t.translation += delta_pos.normalize_or_zero() * PLAYER_SPEED * FRAME_TIME;
+        player_v.linvel = delta_pos.normalize_or_zero().truncate() * PLAYER_SPEED;
 
         let mut bullet_speed = Vec3{x: 0., y: 0., z: 0.};
 
@@ -218,7 +664,7 @@ fn move_players(
         if input & INPUT_LEFT2  != 0 {bullet_speed.x -= 1.;}
 
         bullet_speed = bullet_speed.normalize_or_zero();
-        
+
         if bullet_speed.length() != 0. && player.reload_time <= 0. {
             commands.spawn((MaterialMesh2dBundle{
                     mesh: meshes.add(shape::Circle::new(BULLET_SIZE).into()).into(),
@@ -226,9 +672,16 @@ fn move_players(
                     transform: Transform::from_translation(player_t.translation),
                     ..default()
                 },
-                Bullet,
-                Velocity{val: bullet_speed},
-                LifeTime{val: 1.}
+                Bullet {handle: player.handle},
+                RigidBody::Dynamic,
+                Collider::ball(BULLET_SIZE),
+                // Overlap is resolved by hand in `resolve_hits`; without this
+                // Rapier would also apply a real collision impulse on every
+                // hit, shoving players around on top of the damage logic.
+                Sensor,
+                Velocity::linear(bullet_speed.truncate() * BULLET_SPEED),
+                GravityScale(0.),
+                LifeTime{val: BULLET_LIFETIME}
             )).add_rollback();
 
             player.reload_time = TIME_TO_RELOAD;
@@ -242,11 +695,74 @@ fn move_players(
 
 
 
-fn move_objects(
-    mut objects: Query<(&mut Transform, &Velocity), With<Rollback>>
+fn circles_overlap(a: Vec2, b: Vec2, combined_radius: f32) -> bool {
+    a.distance(b) <= combined_radius
+}
+
+fn resolve_hits(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+
+    bullets: Query<(Entity, &Transform, &Bullet, &LifeTime, &Rollback)>,
+    mut players: Query<(Entity, &Transform, &mut Health, &Player, &Rollback)>
 ) {
-    for (mut object_t, object_v) in objects.iter_mut() {
-        object_t.translation += object_v.val * FRAME_TIME * BULLET_SPEED;
+    // Sorted by `Entity` rather than anything rollback-specific: entities are
+    // spawned in the same deterministic order on every peer, so this gives a
+    // stable iteration order without needing any extra bookkeeping.
+    let mut bullet_list: Vec<_> = bullets.iter().collect();
+    bullet_list.sort_by_key(|(entity, _, _, _, _)| *entity);
+
+    let mut player_list: Vec<_> = players.iter()
+        .map(|(entity, player_t, _, player, _)| (entity, *player_t, player.handle))
+        .collect();
+    player_list.sort_by_key(|(entity, _, _)| *entity);
+
+    let mut hit_bullets = Vec::new();
+    let mut damage: HashMap<Entity, i32> = HashMap::new();
+
+    for (bullet_entity, bullet_t, bullet, lifetime, _) in bullet_list.iter() {
+        let in_spawn_grace = lifetime.val > BULLET_LIFETIME - BULLET_SPAWN_GRACE;
+
+        for (player_entity, player_t, player_handle) in player_list.iter() {
+            if bullet.handle == *player_handle && in_spawn_grace {
+                continue;
+            }
+
+            if circles_overlap(bullet_t.translation.truncate(), player_t.translation.truncate(), BULLET_SIZE + PLAYER_SIZE) {
+                *damage.entry(*player_entity).or_insert(0) += BULLET_DAMAGE;
+                hit_bullets.push(*bullet_entity);
+                break;
+            }
+        }
+    }
+
+    for (player_entity, player_t, mut health, player, _) in players.iter_mut() {
+        let Some(dealt) = damage.get(&player_entity) else { continue; };
+        health.val -= dealt;
+
+        if health.val <= 0 {
+            commands.entity(player_entity).despawn();
+
+            commands.spawn((MaterialMesh2dBundle {
+                    mesh: meshes.add(shape::Circle::new(PLAYER_SIZE).into()).into(),
+                    material: materials.add(ColorMaterial::from(Color::Hsla { hue: player.hue, saturation: 0.75, lightness: 0.5, alpha: 1. })),
+                    transform: Transform::from_translation(Vec3 { x: player.handle as f32 * 75., y: 0., z: player_t.translation.z }),
+                    ..default()
+                },
+                Player {handle: player.handle, reload_time: 0., hue: player.hue},
+                Health {val: PLAYER_MAX_HEALTH},
+                RigidBody::Dynamic,
+                Collider::ball(PLAYER_SIZE),
+                Velocity::zero(),
+                GravityScale(0.),
+                LockedAxes::ROTATION_LOCKED,
+            )).add_rollback();
+        }
+    }
+
+    for bullet_entity in hit_bullets {
+        commands.entity(bullet_entity).despawn();
     }
 }
 
@@ -265,4 +781,185 @@ fn age_mortals(
             commands.entity(entity).despawn();
         }
     }
+}
+
+
+
+
+fn setup_hud(
+    mut commands: Commands,
+    session: Res<Session<GgrsConfig>>
+) {
+    let pnum = match &*session {
+        Session::SyncTest(s) => s.num_players(),
+        Session::P2P(s) => s.num_players(),
+        Session::Spectator(s) => s.num_players(),
+    };
+
+    commands.spawn(NodeBundle {
+        style: Style {
+            flex_direction: FlexDirection::Column,
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.),
+            left: Val::Px(10.),
+            ..default()
+        },
+        ..default()
+    }).with_children(|parent| {
+        for i in 0..pnum {
+            parent.spawn((
+                TextBundle::from_section(
+                    format!("P{i} reload: ready"),
+                    TextStyle { font_size: 20., color: Color::WHITE, ..default() }
+                ),
+                ReloadBarText {handle: i},
+            ));
+        }
+
+        parent.spawn((
+            TextBundle::from_section(
+                "frame: 0",
+                TextStyle { font_size: 20., color: Color::WHITE, ..default() }
+            ),
+            NetworkStatsText,
+        ));
+    });
+}
+
+// Runs on the regular `Update` schedule, not `GgrsSchedule`, so these text
+// entities are drawn every render frame and never rolled back.
+fn update_hud(
+    players: Query<&Player>,
+    mut reload_texts: Query<(&mut Text, &ReloadBarText)>,
+    mut stats_text: Query<&mut Text, (With<NetworkStatsText>, Without<ReloadBarText>)>,
+    session: Res<Session<GgrsConfig>>,
+) {
+    for (mut text, bar) in reload_texts.iter_mut() {
+        let Some(player) = players.iter().find(|player| player.handle == bar.handle) else { continue; };
+
+        text.sections[0].value = if player.reload_time <= 0. {
+            format!("P{} reload: ready", bar.handle)
+        } else {
+            format!("P{} reload: {:.2}s", bar.handle, player.reload_time)
+        };
+    }
+
+    let Ok(mut text) = stats_text.get_single_mut() else { return; };
+
+    text.sections[0].value = match &*session {
+        Session::P2P(s) => {
+            let mut line = format!("frame: {}", s.current_frame());
+
+            for handle in s.remote_player_handles() {
+                if let Ok(stats) = s.network_stats(handle) {
+                    line.push_str(&format!(" | p{handle} ping {}ms ahead {}", stats.ping, -stats.local_frames_behind));
+                }
+            }
+
+            line
+        }
+        // Neither session type exposes its own frame counter.
+        Session::SyncTest(_) => "(synctest)".to_string(),
+        Session::Spectator(_) => "(spectator)".to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_circles_register_a_hit() {
+        assert!(circles_overlap(Vec2::new(0., 0.), Vec2::new(5., 0.), BULLET_SIZE + PLAYER_SIZE));
+    }
+
+    #[test]
+    fn circles_further_apart_than_their_combined_radius_miss() {
+        let just_out_of_range = BULLET_SIZE + PLAYER_SIZE + 0.01;
+        assert!(!circles_overlap(Vec2::new(0., 0.), Vec2::new(just_out_of_range, 0.), BULLET_SIZE + PLAYER_SIZE));
+    }
+
+    #[test]
+    fn circles_exactly_at_combined_radius_register_a_hit() {
+        let combined = BULLET_SIZE + PLAYER_SIZE;
+        assert!(circles_overlap(Vec2::new(0., 0.), Vec2::new(combined, 0.), combined));
+    }
+
+    fn remote_keys_for(handle: PlayerHandle, key: SigningKey) -> RemoteKeys {
+        let mut keys = HashMap::new();
+        keys.insert(handle, key.verifying_key());
+        RemoteKeys { enabled: true, keys, last_frame: HashMap::new() }
+    }
+
+    #[test]
+    fn verify_input_accepts_a_correctly_signed_input() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+
+        let mut signed = [0u8; 5];
+        signed[0] = INPUT_UP;
+        signed[1..].copy_from_slice(&42u32.to_le_bytes());
+
+        let box_input = BoxInput {
+            val: INPUT_UP,
+            frame: 42u32.to_le_bytes(),
+            signature: key.sign(&signed).to_bytes(),
+        };
+
+        assert!(verify_input(&box_input, 0, &remote_keys_for(0, key)));
+    }
+
+    #[test]
+    fn verify_input_rejects_a_tampered_input() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+
+        let mut signed = [0u8; 5];
+        signed[0] = INPUT_UP;
+        signed[1..].copy_from_slice(&42u32.to_le_bytes());
+
+        let mut box_input = BoxInput {
+            val: INPUT_UP,
+            frame: 42u32.to_le_bytes(),
+            signature: key.sign(&signed).to_bytes(),
+        };
+        box_input.val = INPUT_DOWN;
+
+        assert!(!verify_input(&box_input, 0, &remote_keys_for(0, key)));
+    }
+
+    #[test]
+    fn verify_input_rejects_an_unknown_handle() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+
+        let mut signed = [0u8; 5];
+        signed[0] = INPUT_UP;
+        signed[1..].copy_from_slice(&42u32.to_le_bytes());
+
+        let box_input = BoxInput {
+            val: INPUT_UP,
+            frame: 42u32.to_le_bytes(),
+            signature: key.sign(&signed).to_bytes(),
+        };
+
+        // Signed by a key the player 1 slot never registered.
+        assert!(!verify_input(&box_input, 1, &remote_keys_for(0, key)));
+    }
+
+    #[test]
+    fn spawn_layout_spaces_players_evenly_around_a_circle_of_hues() {
+        let spawns = spawn_layout(4);
+
+        assert_eq!(spawns.len(), 4);
+        assert_eq!(spawns[0].hue, 0.);
+        assert_eq!(spawns[1].hue, 90.);
+        assert_eq!(spawns[2].hue, 180.);
+        assert_eq!(spawns[3].hue, 270.);
+    }
+
+    #[test]
+    fn spawn_layout_is_deterministic() {
+        assert_eq!(
+            spawn_layout(3).iter().map(|s| s.translation).collect::<Vec<_>>(),
+            spawn_layout(3).iter().map(|s| s.translation).collect::<Vec<_>>(),
+        );
+    }
 }
\ No newline at end of file